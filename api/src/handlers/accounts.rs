@@ -0,0 +1,251 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, SaltString};
+use argon2::{PasswordHasher, PasswordVerifier};
+use lettre::message::Mailbox;
+use lettre::Address;
+use log::{error, warn};
+use paperclip::actix::web::{Data, Json};
+use paperclip::actix::{api_v2_operation, Apiv2Schema, NoContent};
+use serde::{Deserialize, Serialize};
+use sqlx::query;
+use std::str::FromStr;
+use validator::Validate;
+
+use crate::handlers::registrations::{build_argon2, map_unique_violation};
+use crate::iam::{create_email_change_token, verify_email_change_token, AuthenticatedUser};
+use crate::mailer::Mail;
+use crate::problems::Hook0Problem;
+
+#[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
+pub struct ChangePasswordPost {
+    current_password: String,
+    #[validate(
+        non_control_character,
+        length(
+            min = 10,
+            max = 100,
+            message = "Password must be at least 10 characters long and at most 100 characters long"
+        )
+    )]
+    new_password: String,
+}
+
+#[api_v2_operation(
+    summary = "Change the current account's password",
+    description = "",
+    operation_id = "changePassword",
+    consumes = "application/json",
+    produces = "application/json",
+    tags("Organizations Management")
+)]
+pub async fn post_password(
+    state: Data<crate::State>,
+    auth: AuthenticatedUser,
+    body: Json<ChangePasswordPost>,
+) -> Result<NoContent, Hook0Problem> {
+    if let Err(e) = body.validate() {
+        return Err(Hook0Problem::Validation(e));
+    }
+
+    if body.new_password.len() < usize::from(state.password_minimum_length) {
+        return Err(Hook0Problem::PasswordTooShort(
+            state.password_minimum_length,
+        ));
+    }
+
+    let mut tx = state.db.begin().await?;
+
+    let user = query!(
+        "SELECT password FROM iam.user WHERE user__id = $1",
+        &auth.user_id,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let parsed_hash = PasswordHash::new(&user.password).map_err(|e| {
+        error!("Error trying to parse stored password hash: {e}");
+        Hook0Problem::InternalServerError
+    })?;
+    let argon2 = build_argon2(&state.argon2_params)?;
+    if argon2
+        .verify_password(body.current_password.as_bytes(), &parsed_hash)
+        .is_err()
+    {
+        return Err(Hook0Problem::InvalidCurrentPassword);
+    }
+
+    let salt = SaltString::generate(&mut OsRng);
+    let new_password_hash = argon2
+        .hash_password(body.new_password.as_bytes(), &salt)
+        .map_err(|e| {
+            error!("Error trying to hash user password: {e}");
+            Hook0Problem::InternalServerError
+        })?
+        .serialize();
+
+    query!(
+        "UPDATE iam.user SET password = $1 WHERE user__id = $2",
+        new_password_hash.as_str(),
+        &auth.user_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(NoContent)
+}
+
+#[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
+pub struct ChangeEmailPost {
+    #[validate(non_control_character, email, length(max = 100))]
+    new_email: String,
+}
+
+#[api_v2_operation(
+    summary = "Request a change of the current account's e-mail address",
+    description = "The change only takes effect once the confirmation link sent to the new address is followed.",
+    operation_id = "changeEmail",
+    consumes = "application/json",
+    produces = "application/json",
+    tags("Organizations Management")
+)]
+pub async fn post_email(
+    state: Data<crate::State>,
+    auth: AuthenticatedUser,
+    body: Json<ChangeEmailPost>,
+) -> Result<NoContent, Hook0Problem> {
+    if let Err(e) = body.validate() {
+        return Err(Hook0Problem::Validation(e));
+    }
+
+    let recipient_address = Address::from_str(&body.new_email).map_err(|e| {
+        // Should not happen because we checked (using a validator) that body.new_email is a well structured email address
+        error!("Error trying to parse email address: {e}");
+        Hook0Problem::InternalServerError
+    })?;
+
+    let change_token = create_email_change_token(&state.biscuit_private_key, auth.user_id, &body.new_email)
+        .map_err(|e| {
+            error!("Error trying to create email change token: {e}");
+            Hook0Problem::InternalServerError
+        })?;
+
+    let recipient = Mailbox::new(None, recipient_address);
+    state
+        .mailer
+        .send_mail(
+            Mail::VerifyUserEmail {
+                url: format!(
+                    "{}/confirm-email-change?token={}",
+                    state.app_url, &change_token.serialized_biscuit
+                ),
+            },
+            recipient,
+        )
+        .await
+        .map_err(|e| {
+            warn!("Could not send email change confirmation: {e}");
+            e
+        })?;
+
+    // The old address stays active in `iam.user.email` until the new one is confirmed, so the
+    // account keeps working (and can still be reached) if the confirmation link is never followed.
+    Ok(NoContent)
+}
+
+#[derive(Debug, Deserialize, Apiv2Schema)]
+pub struct ConfirmEmailChangeQuery {
+    token: String,
+}
+
+#[api_v2_operation(
+    summary = "Confirm a pending e-mail change",
+    description = "",
+    operation_id = "confirmEmailChange",
+    consumes = "application/json",
+    produces = "application/json",
+    tags("Organizations Management")
+)]
+pub async fn confirm_email_change(
+    state: Data<crate::State>,
+    query: paperclip::actix::web::Query<ConfirmEmailChangeQuery>,
+) -> Result<NoContent, Hook0Problem> {
+    let change = verify_email_change_token(&state.biscuit_private_key, &query.token).map_err(|e| {
+        warn!("Error trying to verify email change token: {e}");
+        Hook0Problem::InvalidEmailChangeToken
+    })?;
+
+    if let Err(e) = query!(
+        "UPDATE iam.user SET email = $1 WHERE user__id = $2",
+        &change.new_email,
+        &change.user_id,
+    )
+    .execute(&state.db)
+    .await
+    {
+        return Err(map_unique_violation(
+            e,
+            "user_email_key",
+            Hook0Problem::UserAlreadyExist,
+        ));
+    }
+
+    Ok(NoContent)
+}
+
+#[api_v2_operation(
+    summary = "Delete the current account",
+    description = "Organizations of which the user is the sole member are deleted along with the account. Organizations shared with other members are simply left (the user's membership is removed) rather than deleted or blocking account deletion.",
+    operation_id = "deleteAccount",
+    consumes = "application/json",
+    produces = "application/json",
+    tags("Organizations Management")
+)]
+pub async fn delete_account(
+    state: Data<crate::State>,
+    auth: AuthenticatedUser,
+) -> Result<NoContent, Hook0Problem> {
+    let mut tx = state.db.begin().await?;
+
+    let memberships = query!(
+        "
+            SELECT organization__id,
+                   (SELECT COUNT(*) FROM iam.user__organization AS uo2 WHERE uo2.organization__id = uo.organization__id) AS \"member_count!\"
+            FROM iam.user__organization AS uo
+            WHERE user__id = $1
+        ",
+        &auth.user_id,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    for membership in &memberships {
+        if membership.member_count > 1 {
+            // Shared with other members: leave the organization instead of deleting it, so
+            // accepting an invite into a shared org never becomes a dead end for account deletion.
+            query!(
+                "DELETE FROM iam.user__organization WHERE user__id = $1 AND organization__id = $2",
+                &auth.user_id,
+                &membership.organization__id,
+            )
+            .execute(&mut *tx)
+            .await?;
+        } else {
+            query!(
+                "DELETE FROM iam.organization WHERE organization__id = $1",
+                &membership.organization__id,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    query!("DELETE FROM iam.user WHERE user__id = $1", &auth.user_id,)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(NoContent)
+}
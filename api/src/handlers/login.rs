@@ -0,0 +1,75 @@
+use log::error;
+use paperclip::actix::web::{Data, Json};
+use paperclip::actix::{api_v2_operation, Apiv2Schema, CreatedJson};
+use serde::{Deserialize, Serialize};
+use sqlx::query;
+use validator::Validate;
+
+use crate::handlers::registrations::verify_password_and_rehash;
+use crate::iam::create_session_token;
+use crate::problems::Hook0Problem;
+
+#[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
+pub struct LoginPost {
+    #[validate(non_control_character, email, length(max = 100))]
+    email: String,
+    #[validate(non_control_character, length(min = 1, max = 100))]
+    password: String,
+}
+
+#[derive(Debug, Serialize, Apiv2Schema)]
+pub struct Login {
+    token: String,
+}
+
+#[api_v2_operation(
+    summary = "Authenticate with an e-mail and password",
+    description = "If the stored password hash was produced with Argon2 parameters older than the ones currently configured, it is transparently re-hashed with the new parameters on successful login.",
+    operation_id = "login",
+    consumes = "application/json",
+    produces = "application/json",
+    tags("Organizations Management")
+)]
+pub async fn login(
+    state: Data<crate::State>,
+    body: Json<LoginPost>,
+) -> Result<CreatedJson<Login>, Hook0Problem> {
+    if let Err(e) = body.validate() {
+        return Err(Hook0Problem::Validation(e));
+    }
+
+    let mut tx = state.db.begin().await?;
+
+    let user = query!(
+        "SELECT user__id, password FROM iam.user WHERE email = $1",
+        &body.email,
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Hook0Problem::InvalidCredentials)?;
+
+    let password_is_valid = verify_password_and_rehash(
+        &mut *tx,
+        &state.argon2_params,
+        &user.user__id,
+        &user.password,
+        &body.password,
+    )
+    .await?;
+
+    if !password_is_valid {
+        return Err(Hook0Problem::InvalidCredentials);
+    }
+
+    let session_token = create_session_token(&state.biscuit_private_key, user.user__id)
+        .map_err(|e| {
+            error!("Error trying to create session token: {e}");
+            Hook0Problem::InternalServerError
+        })?;
+
+    tx.commit().await?;
+
+    Ok(CreatedJson(Login {
+        token: session_token.serialized_biscuit,
+    }))
+}
@@ -1,21 +1,177 @@
+use actix_web::HttpRequest;
 use argon2::password_hash::rand_core::OsRng;
-use argon2::password_hash::SaltString;
-use argon2::{Argon2, PasswordHasher};
+use argon2::password_hash::{PasswordHash, SaltString};
+use argon2::{Algorithm, Argon2, Params, PasswordHasher, PasswordVerifier, Version};
+use chrono::Duration;
 use lettre::message::Mailbox;
 use lettre::Address;
 use log::{error, warn};
 use paperclip::actix::web::{Data, Json};
-use paperclip::actix::{api_v2_operation, Apiv2Schema, CreatedJson};
+use paperclip::actix::{api_v2_operation, Apiv2Schema, CreatedJson, NoContent};
 use serde::{Deserialize, Serialize};
 use sqlx::query;
 use std::str::FromStr;
 use uuid::Uuid;
 use validator::Validate;
 
-use crate::iam::{create_email_verification_token, Role};
+use crate::iam::{
+    create_email_verification_token, create_invitation_token, verify_invitation_token,
+    AuthenticatedUser, Role,
+};
 use crate::mailer::Mail;
 use crate::problems::Hook0Problem;
 
+/// How long an e-mail verification token stays valid before `resend-verification` is required.
+const EMAIL_VERIFICATION_TOKEN_VALIDITY_HOURS: i64 = 24;
+
+/// How long an organization invitation stays valid before the invite must be re-sent. Bounds how
+/// long a leaked or stale invite e-mail can be used to join the target organization.
+const INVITATION_TOKEN_VALIDITY_HOURS: i64 = 72;
+
+/// Tunable Argon2 cost parameters, configured once at startup on [`crate::State`] so operators
+/// can raise the KDF cost over time without changing code.
+#[derive(Debug, Clone, Copy)]
+pub struct Argon2Params {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Params {
+    fn default() -> Self {
+        let params = Params::default();
+        Self {
+            memory_kib: params.m_cost(),
+            iterations: params.t_cost(),
+            parallelism: params.p_cost(),
+        }
+    }
+}
+
+/// Pure classification logic behind [`map_unique_violation`], split out so it can be unit tested
+/// without needing a live `sqlx::Error`/`DatabaseError`.
+fn is_matching_unique_violation(
+    is_unique_violation: bool,
+    constraint: Option<&str>,
+    expected_constraint: &str,
+) -> bool {
+    is_unique_violation && constraint == Some(expected_constraint)
+}
+
+/// Inspects a failed query for a unique-constraint violation on `constraint_name`, mapping it to
+/// `conflict_problem` and any other database error to `Hook0Problem::InternalServerError`. This
+/// makes duplicate detection authoritative (instead of relying on `ON CONFLICT ... DO NOTHING`
+/// plus a `rows_affected()` check, which can't tell "already exists" apart from other no-op
+/// outcomes) and reusable by the invite and e-mail-change flows.
+pub(crate) fn map_unique_violation(
+    e: sqlx::Error,
+    constraint_name: &str,
+    conflict_problem: Hook0Problem,
+) -> Hook0Problem {
+    match e.as_database_error() {
+        Some(db_err)
+            if is_matching_unique_violation(
+                db_err.is_unique_violation(),
+                db_err.constraint(),
+                constraint_name,
+            ) =>
+        {
+            conflict_problem
+        }
+        _ => {
+            error!("Database error: {e}");
+            Hook0Problem::InternalServerError
+        }
+    }
+}
+
+/// The direct TCP peer address, used as the rate limiter's per-IP key. This deliberately does not
+/// use `ConnectionInfo::realip_remote_addr()`/the `Forwarded`/`X-Forwarded-For` headers: this
+/// series configures no trusted-proxy allowlist, so those headers are attacker-controlled and a
+/// caller could set a fresh one on every request to bypass the per-IP limit entirely. If Hook0 is
+/// deployed behind a reverse proxy, trusted-proxy support should be added to `crate::State`
+/// alongside this rate limiter before switching back to the forwarded-for address.
+fn client_ip(req: &HttpRequest) -> String {
+    req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+pub(crate) fn build_argon2(params: &Argon2Params) -> Result<Argon2<'static>, Hook0Problem> {
+    let hasher_params = Params::new(
+        params.memory_kib,
+        params.iterations,
+        params.parallelism,
+        None,
+    )
+    .map_err(|e| {
+        error!("Invalid Argon2 params: {e}");
+        Hook0Problem::InternalServerError
+    })?;
+
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, hasher_params))
+}
+
+/// Pure comparison behind [`verify_password_and_rehash`]'s rehash decision, split out so it can be
+/// unit tested against real [`PasswordHash`] values without needing a live DB.
+fn hash_params_match(parsed_hash: &PasswordHash, configured: &Argon2Params) -> bool {
+    parsed_hash
+        .params
+        .iter()
+        .all(|(name, value)| match name.as_str() {
+            "m" => value.decimal().ok() == Some(configured.memory_kib),
+            "t" => value.decimal().ok() == Some(configured.iterations),
+            "p" => value.decimal().ok() == Some(configured.parallelism),
+            _ => true,
+        })
+}
+
+/// Verifies `plaintext` against `stored_hash`. If the hash was produced with parameters that
+/// differ from the currently configured [`Argon2Params`], the password is transparently re-hashed
+/// with the new params and `iam.user.password` is updated within the caller's transaction. Used by
+/// [`crate::handlers::login::login`] to upgrade KDF cost on next login.
+pub async fn verify_password_and_rehash(
+    tx: &mut sqlx::PgConnection,
+    argon2_params: &Argon2Params,
+    user_id: &Uuid,
+    stored_hash: &str,
+    plaintext: &str,
+) -> Result<bool, Hook0Problem> {
+    let parsed_hash = PasswordHash::new(stored_hash).map_err(|e| {
+        error!("Error trying to parse stored password hash: {e}");
+        Hook0Problem::InternalServerError
+    })?;
+    let current_argon2 = build_argon2(argon2_params)?;
+
+    if current_argon2
+        .verify_password(plaintext.as_bytes(), &parsed_hash)
+        .is_err()
+    {
+        return Ok(false);
+    }
+
+    if !hash_params_match(&parsed_hash, argon2_params) {
+        let salt = SaltString::generate(&mut OsRng);
+        let rehashed = current_argon2
+            .hash_password(plaintext.as_bytes(), &salt)
+            .map_err(|e| {
+                error!("Error trying to re-hash user password: {e}");
+                Hook0Problem::InternalServerError
+            })?
+            .serialize();
+
+        query!(
+            "UPDATE iam.user SET password = $1 WHERE user__id = $2",
+            rehashed.as_str(),
+            user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    Ok(true)
+}
+
 #[derive(Debug, Serialize, Apiv2Schema)]
 pub struct Registration {
     organization_id: Uuid,
@@ -39,11 +195,112 @@ pub struct RegistrationPost {
         )
     )]
     password: String,
+    /// Invite token received by e-mail when an existing organization member invited this address.
+    /// When present, the account is attached to that organization/role instead of creating a new
+    /// personal organization.
+    invite_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Apiv2Schema)]
+pub struct Invitation {
+    organization_id: Uuid,
+    email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
+pub struct InvitationPost {
+    #[validate(non_control_character, email, length(max = 100))]
+    email: String,
+    organization_id: Uuid,
+    role: Role,
 }
 
 #[api_v2_operation(
-    summary = "Create a new user account and its own personal organization",
+    summary = "Invite a user to join an organization",
     description = "",
+    operation_id = "invite",
+    consumes = "application/json",
+    produces = "application/json",
+    tags("Organizations Management")
+)]
+pub async fn invite(
+    state: Data<crate::State>,
+    req: HttpRequest,
+    auth: AuthenticatedUser,
+    body: Json<InvitationPost>,
+) -> Result<CreatedJson<Invitation>, Hook0Problem> {
+    if let Err(e) = body.validate() {
+        return Err(Hook0Problem::Validation(e));
+    }
+
+    state
+        .rate_limiter
+        .check("invite", &client_ip(&req), &body.email)
+        .await?;
+
+    let recipient_address = Address::from_str(&body.email).map_err(|e| {
+        // Should not happen because we checked (using a validator) that body.email is a well structured email address
+        error!("Error trying to parse email address: {e}");
+        Hook0Problem::InternalServerError
+    })?;
+
+    let inviter_is_editor = query!(
+        "
+            SELECT 1 AS \"exists!\"
+            FROM iam.user__organization
+            WHERE user__id = $1 AND organization__id = $2 AND role = $3
+        ",
+        &auth.user_id,
+        &body.organization_id,
+        Role::Editor.as_ref(),
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .is_some();
+
+    if !inviter_is_editor {
+        return Err(Hook0Problem::Forbidden);
+    }
+
+    let invitation_token = create_invitation_token(
+        &state.biscuit_private_key,
+        &body.email,
+        body.organization_id,
+        body.role,
+        Duration::hours(INVITATION_TOKEN_VALIDITY_HOURS),
+    )
+    .map_err(|e| {
+        error!("Error trying to create invitation token: {e}");
+        Hook0Problem::InternalServerError
+    })?;
+
+    let recipient = Mailbox::new(None, recipient_address);
+    state
+        .mailer
+        .send_mail(
+            Mail::InviteUser {
+                url: format!(
+                    "{}/register?invite_token={}",
+                    state.app_url, &invitation_token.serialized_biscuit
+                ),
+            },
+            recipient,
+        )
+        .await
+        .map_err(|e| {
+            warn!("Could not send invitation email: {e}");
+            e
+        })?;
+
+    Ok(CreatedJson(Invitation {
+        organization_id: body.organization_id,
+        email: body.email.clone(),
+    }))
+}
+
+#[api_v2_operation(
+    summary = "Create a new user account",
+    description = "Without an invite token, this also creates a personal organization owned by the new user. With a valid `invite_token`, the user is instead attached to the invited organization with the invited role and no personal organization is created.",
     operation_id = "register",
     consumes = "application/json",
     produces = "application/json",
@@ -51,6 +308,7 @@ pub struct RegistrationPost {
 )]
 pub async fn register(
     state: Data<crate::State>,
+    req: HttpRequest,
     body: Json<RegistrationPost>,
 ) -> Result<CreatedJson<Registration>, Hook0Problem> {
     if state.registration_disabled {
@@ -61,6 +319,11 @@ pub async fn register(
         return Err(Hook0Problem::Validation(e));
     }
 
+    state
+        .rate_limiter
+        .check("register", &client_ip(&req), &body.email)
+        .await?;
+
     let recipient_address = Address::from_str(&body.email).map_err(|e| {
         // Should not happen because we checked (using a validator) that body.email is a well structured email address
         error!("Error trying to parse email address: {e}");
@@ -72,18 +335,17 @@ pub async fn register(
 
         let user_id = Uuid::new_v4();
         let salt = SaltString::generate(&mut OsRng);
-        let password_hash = Argon2::default()
+        let password_hash = build_argon2(&state.argon2_params)?
             .hash_password(body.password.as_bytes(), &salt)
             .map_err(|e| {
                 error!("Error trying to hash user password: {e}");
                 Hook0Problem::InternalServerError
             })?
             .serialize();
-        let user_insert = query!(
+        if let Err(e) = query!(
             "
                 INSERT INTO iam.user (user__id, email, password, first_name, last_name)
                 VALUES ($1, $2, $3, $4, $5)
-                ON CONFLICT (email) DO NOTHING
             ",
             &user_id,
             &body.email,
@@ -92,9 +354,40 @@ pub async fn register(
             &body.last_name,
         )
         .execute(&mut *tx)
-        .await?;
+        .await
+        {
+            return Err(map_unique_violation(
+                e,
+                "user_email_key",
+                Hook0Problem::UserAlreadyExist,
+            ));
+        }
+
+        let organization_id = if let Some(invite_token) = &body.invite_token {
+            let invitation = verify_invitation_token(&state.biscuit_private_key, invite_token)
+                .map_err(|e| {
+                    warn!("Error trying to verify invitation token: {e}");
+                    Hook0Problem::InvalidInvitationToken
+                })?;
+
+            if invitation.email != body.email {
+                return Err(Hook0Problem::InvalidInvitationToken);
+            }
+
+            query!(
+                "
+                    INSERT INTO iam.user__organization (user__id, organization__id, role)
+                    VALUES ($1, $2, $3)
+                ",
+                &user_id,
+                &invitation.organization_id,
+                invitation.role.as_ref(),
+            )
+            .execute(&mut *tx)
+            .await?;
 
-        if user_insert.rows_affected() > 0 {
+            invitation.organization_id
+        } else {
             let organization_id = Uuid::new_v4();
             let organization_name = format!(
                 "{} {}'s personal organization",
@@ -124,15 +417,104 @@ pub async fn register(
             .execute(&mut *tx)
             .await?;
 
-            let verification_token =
-                create_email_verification_token(&state.biscuit_private_key, user_id).map_err(
-                    |e| {
-                        error!("Error trying to create email verification token: {e}");
-                        Hook0Problem::InternalServerError
-                    },
-                )?;
+            organization_id
+        };
+
+        let verification_token = create_email_verification_token(
+            &state.biscuit_private_key,
+            user_id,
+            Duration::hours(EMAIL_VERIFICATION_TOKEN_VALIDITY_HOURS),
+        )
+        .map_err(|e| {
+            error!("Error trying to create email verification token: {e}");
+            Hook0Problem::InternalServerError
+        })?;
+        let recipient = Mailbox::new(
+            Some(format!("{} {}", body.first_name, body.last_name)),
+            recipient_address,
+        );
+        state
+            .mailer
+            .send_mail(
+                Mail::VerifyUserEmail {
+                    url: format!(
+                        "{}/verify-email?token={}",
+                        state.app_url, &verification_token.serialized_biscuit
+                    ),
+                },
+                recipient,
+            )
+            .await
+            .map_err(|e| {
+                warn!("Could not send verification email: {e}");
+                e
+            })?;
+
+        tx.commit().await?;
+
+        Ok(CreatedJson(Registration {
+            organization_id,
+            user_id,
+        }))
+    } else {
+        Err(Hook0Problem::PasswordTooShort(
+            state.password_minimum_length,
+        ))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
+pub struct ResendVerificationPost {
+    #[validate(non_control_character, email, length(max = 100))]
+    email: String,
+}
+
+#[api_v2_operation(
+    summary = "Resend the e-mail verification link",
+    description = "Always succeeds, whether or not an account exists for the given address, so this endpoint can't be used to enumerate registered users.",
+    operation_id = "resendVerification",
+    consumes = "application/json",
+    produces = "application/json",
+    tags("Organizations Management")
+)]
+pub async fn resend_verification(
+    state: Data<crate::State>,
+    req: HttpRequest,
+    body: Json<ResendVerificationPost>,
+) -> Result<NoContent, Hook0Problem> {
+    if let Err(e) = body.validate() {
+        return Err(Hook0Problem::Validation(e));
+    }
+
+    state
+        .rate_limiter
+        .check("resend_verification", &client_ip(&req), &body.email)
+        .await?;
+
+    if let Some(user) = query!(
+        "SELECT user__id, first_name, last_name, verified FROM iam.user WHERE email = $1",
+        &body.email,
+    )
+    .fetch_optional(&state.db)
+    .await?
+    {
+        if !user.verified {
+            let recipient_address = Address::from_str(&body.email).map_err(|e| {
+                // Should not happen because we checked (using a validator) that body.email is a well structured email address
+                error!("Error trying to parse email address: {e}");
+                Hook0Problem::InternalServerError
+            })?;
+            let verification_token = create_email_verification_token(
+                &state.biscuit_private_key,
+                user.user__id,
+                Duration::hours(EMAIL_VERIFICATION_TOKEN_VALIDITY_HOURS),
+            )
+            .map_err(|e| {
+                error!("Error trying to create email verification token: {e}");
+                Hook0Problem::InternalServerError
+            })?;
             let recipient = Mailbox::new(
-                Some(format!("{} {}", body.first_name, body.last_name)),
+                Some(format!("{} {}", user.first_name, user.last_name)),
                 recipient_address,
             );
             state
@@ -151,19 +533,86 @@ pub async fn register(
                     warn!("Could not send verification email: {e}");
                     e
                 })?;
+        }
+    }
 
-            tx.commit().await?;
+    Ok(NoContent)
+}
 
-            Ok(CreatedJson(Registration {
-                organization_id,
-                user_id,
-            }))
-        } else {
-            Err(Hook0Problem::UserAlreadyExist)
-        }
-    } else {
-        Err(Hook0Problem::PasswordTooShort(
-            state.password_minimum_length,
-        ))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unique_violation_matches_expected_constraint() {
+        assert!(is_matching_unique_violation(
+            true,
+            Some("user_email_key"),
+            "user_email_key"
+        ));
+    }
+
+    #[test]
+    fn unique_violation_on_a_different_constraint_does_not_match() {
+        assert!(!is_matching_unique_violation(
+            true,
+            Some("some_other_key"),
+            "user_email_key"
+        ));
+    }
+
+    #[test]
+    fn non_unique_violation_does_not_match() {
+        assert!(!is_matching_unique_violation(
+            false,
+            Some("user_email_key"),
+            "user_email_key"
+        ));
+    }
+
+    #[test]
+    fn missing_constraint_name_does_not_match() {
+        assert!(!is_matching_unique_violation(true, None, "user_email_key"));
+    }
+
+    fn hash_with_params(params: &Argon2Params) -> String {
+        let salt = SaltString::generate(&mut OsRng);
+        build_argon2(params)
+            .unwrap()
+            .hash_password(b"hunter22", &salt)
+            .unwrap()
+            .serialize()
+            .to_string()
+    }
+
+    #[test]
+    fn hash_params_match_returns_true_for_matching_params() {
+        let params = Argon2Params {
+            memory_kib: 19_456,
+            iterations: 2,
+            parallelism: 1,
+        };
+        let serialized = hash_with_params(&params);
+        let parsed = PasswordHash::new(&serialized).unwrap();
+
+        assert!(hash_params_match(&parsed, &params));
+    }
+
+    #[test]
+    fn hash_params_match_returns_false_when_cost_was_raised() {
+        let old_params = Argon2Params {
+            memory_kib: 19_456,
+            iterations: 2,
+            parallelism: 1,
+        };
+        let serialized = hash_with_params(&old_params);
+        let parsed = PasswordHash::new(&serialized).unwrap();
+
+        let new_params = Argon2Params {
+            memory_kib: 65_536,
+            iterations: 3,
+            parallelism: 1,
+        };
+        assert!(!hash_params_match(&parsed, &new_params));
     }
 }
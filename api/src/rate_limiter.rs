@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::error;
+use redis::AsyncCommands;
+
+use crate::problems::Hook0Problem;
+
+/// Window length for both the Redis and in-process limiters. A key's window starts at its first
+/// attempt and its count resets once the window has elapsed (a fixed window, not a sliding one).
+const WINDOW_SECONDS: usize = 60;
+const MAX_ATTEMPTS_PER_IP: usize = 20;
+const MAX_ATTEMPTS_PER_EMAIL: usize = 8;
+
+/// Rate limiter for the unauthenticated, abuse-prone registration/invite/resend endpoints:
+/// these do expensive Argon2 hashing and send e-mails before any authentication check, so they
+/// are throttled per client IP and per target e-mail address.
+///
+/// Backed by Redis (shared across nodes) when `crate::State` is configured with one, otherwise
+/// falls back to an in-process fixed-window counter so single-node deployments are still protected.
+#[derive(Clone)]
+pub enum RateLimiter {
+    Redis(redis::aio::ConnectionManager),
+    InProcess(Arc<Mutex<HashMap<String, (usize, Instant)>>>),
+}
+
+impl RateLimiter {
+    pub fn redis(conn: redis::aio::ConnectionManager) -> Self {
+        RateLimiter::Redis(conn)
+    }
+
+    pub fn in_process() -> Self {
+        RateLimiter::InProcess(Arc::new(Mutex::new(HashMap::new())))
+    }
+
+    /// Rejects the request with [`Hook0Problem::TooManyRequests`] if either the client IP or the
+    /// target e-mail address has exceeded its allowance for the current window.
+    ///
+    /// `scope` namespaces the per-email counter to the calling endpoint (e.g. `"register"`,
+    /// `"invite"`, `"resend_verification"`) so that hitting the limit on one endpoint with a
+    /// given e-mail address doesn't also lock that address out of the others.
+    pub async fn check(&self, scope: &str, ip: &str, email: &str) -> Result<(), Hook0Problem> {
+        self.check_key(&format!("rate_limit:ip:{scope}:{ip}"), MAX_ATTEMPTS_PER_IP)
+            .await?;
+        self.check_key(
+            &format!("rate_limit:email:{scope}:{email}"),
+            MAX_ATTEMPTS_PER_EMAIL,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn check_key(&self, key: &str, max_attempts: usize) -> Result<(), Hook0Problem> {
+        let count = match self {
+            RateLimiter::Redis(conn) => {
+                let mut conn = conn.clone();
+                let count: usize = conn.incr(key, 1_usize).await.map_err(|e| {
+                    error!("Error trying to increment rate limit counter in Redis: {e}");
+                    Hook0Problem::InternalServerError
+                })?;
+                if count == 1 {
+                    let _: () = conn
+                        .expire(key, WINDOW_SECONDS as i64)
+                        .await
+                        .map_err(|e| {
+                            error!("Error trying to set rate limit counter expiry in Redis: {e}");
+                            Hook0Problem::InternalServerError
+                        })?;
+                }
+                count
+            }
+            RateLimiter::InProcess(counters) => {
+                let mut counters = counters.lock().map_err(|e| {
+                    error!("Error trying to lock in-process rate limiter: {e}");
+                    Hook0Problem::InternalServerError
+                })?;
+                count_in_process(
+                    &mut counters,
+                    key,
+                    Instant::now(),
+                    Duration::from_secs(WINDOW_SECONDS as u64),
+                )
+            }
+        };
+
+        if count > max_attempts {
+            Err(Hook0Problem::TooManyRequests)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Pure logic behind the in-process fallback, split out of [`RateLimiter::check_key`] so it can be
+/// unit tested with an injected clock instead of waiting out a real `WINDOW_SECONDS`. Evicts
+/// entries whose window has already elapsed as of `now` on every call (otherwise an attacker
+/// cycling through distinct IPs/emails would grow `counters` without bound for the lifetime of the
+/// process), then increments and returns `key`'s count for the current window.
+fn count_in_process(
+    counters: &mut HashMap<String, (usize, Instant)>,
+    key: &str,
+    now: Instant,
+    window: Duration,
+) -> usize {
+    counters.retain(|_, (_, last_seen)| now.duration_since(*last_seen) <= window);
+
+    let entry = counters.entry(key.to_owned()).or_insert((0, now));
+    entry.0 += 1;
+    entry.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_increment_within_the_window() {
+        let mut counters = HashMap::new();
+        let now = Instant::now();
+        let window = Duration::from_secs(60);
+
+        assert_eq!(count_in_process(&mut counters, "k", now, window), 1);
+        assert_eq!(count_in_process(&mut counters, "k", now, window), 2);
+        assert_eq!(count_in_process(&mut counters, "k", now, window), 3);
+    }
+
+    #[test]
+    fn stale_entries_are_evicted_and_do_not_grow_the_map_unbounded() {
+        let mut counters = HashMap::new();
+        let now = Instant::now();
+        let window = Duration::from_secs(60);
+
+        // Simulate an entry left over from a window that has already elapsed.
+        let stale_at = now.checked_sub(window + Duration::from_secs(1)).unwrap();
+        counters.insert("stale".to_owned(), (5, stale_at));
+
+        let count = count_in_process(&mut counters, "fresh", now, window);
+
+        assert_eq!(count, 1);
+        assert!(
+            !counters.contains_key("stale"),
+            "stale key should have been evicted, got: {counters:?}"
+        );
+        assert_eq!(counters.len(), 1);
+    }
+
+    #[test]
+    fn entries_within_the_window_are_not_evicted() {
+        let mut counters = HashMap::new();
+        let now = Instant::now();
+        let window = Duration::from_secs(60);
+
+        let recent_at = now.checked_sub(Duration::from_secs(1)).unwrap();
+        counters.insert("recent".to_owned(), (1, recent_at));
+
+        count_in_process(&mut counters, "other", now, window);
+
+        assert!(counters.contains_key("recent"));
+    }
+
+    #[tokio::test]
+    async fn email_counters_are_namespaced_per_scope() {
+        let limiter = RateLimiter::in_process();
+
+        for _ in 0..MAX_ATTEMPTS_PER_EMAIL {
+            limiter
+                .check("register", "127.0.0.1", "user@example.com")
+                .await
+                .expect("register attempts should not yet be rate limited");
+        }
+
+        // A different scope for the same e-mail address must not have been exhausted by the
+        // "register" attempts above.
+        limiter
+            .check("invite", "127.0.0.1", "user@example.com")
+            .await
+            .expect("invite should have its own counter, independent from register");
+    }
+}